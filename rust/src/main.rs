@@ -9,7 +9,7 @@ async fn main() {
     let container = std::env::var("COSMOS_CONTAINER").expect("COSMOS_CONTAINER is not set");
     let config = store::AzureCosmosKeyValueRuntimeConfig {
         account,
-        key,
+        auth: store::AzureCosmosAuthConfig::Key { key },
         database,
         container,
     };
@@ -19,6 +19,7 @@ async fn main() {
         Some("default".to_string()),
         config,
     )
+    .await
     .unwrap();
 
     // set key
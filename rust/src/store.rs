@@ -1,10 +1,26 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
 use anyhow::Result;
+use azure_core::{auth::TokenCredential, prelude::IfMatchCondition, Etag};
 use azure_data_cosmos::{
-    prelude::{AuthorizationToken, CollectionClient, CosmosClient, Query},
+    prelude::{AuthorizationToken, ChangeFeedMode, CollectionClient, CosmosClient, Param, Query},
     CosmosEntity,
 };
-use futures::StreamExt;
+use base64::Engine;
+use futures::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+const BULK_UPSERT_SPROC_NAME: &str = "bulkUpsert";
+const BULK_UPSERT_SPROC_BODY: &str = include_str!("stored_procedures/bulk_upsert.js");
+const BULK_DELETE_SPROC_NAME: &str = "bulkDelete";
+const BULK_DELETE_SPROC_BODY: &str = include_str!("stored_procedures/bulk_delete.js");
 
 pub struct AzureKeyValueStore {
     app_id: Option<String>,
@@ -12,9 +28,6 @@ pub struct AzureKeyValueStore {
 
 impl AzureKeyValueStore {
     /// Creates a new `AzureKeyValueStore`.
-    ///
-    /// When `app_id` is provided, the store will a partition key of `$app_id/$store_name`,
-    /// otherwise the partition key will be `id`.
     pub fn new(app_id: Option<String>) -> Self {
         Self { app_id }
     }
@@ -22,39 +35,80 @@ impl AzureKeyValueStore {
 
 pub struct KeyValueAzureCosmos {
     client: CollectionClient,
-    /// An optional app id
+    /// An optional app id, scoping this store at the application layer.
     ///
-    /// If provided, the store will handle multiple stores per container using a
-    /// partition key of `/$app_id/$store_name`, otherwise there will be one container
-    /// per store, and the partition key will be `/id`.
+    /// Unlike `store_id`, this plays no part in the Cosmos partition key --
+    /// see `CosmosEntity::partition_key` for `Pair`/`CausalPair`, which uses
+    /// `store_id` (falling back to `id`) and never `app_id`.
     app_id: Option<String>,
     /// An optional store id to use as a partition key for all operations.
     ///
     /// If the store id not set, the store will use `/id` as the partition key.
     store_id: Option<String>,
+    /// A random id identifying this store instance as a writer, attached to
+    /// the dots in the causal context written by [`Self::set_causal`].
+    node_id: String,
+    /// Set once `bulkUpsert` has been registered, so [`Self::bulk_upsert`]
+    /// only pays for a `create_stored_procedure` round trip on first use.
+    bulk_upsert_sproc_registered: AtomicBool,
+    /// Set once `bulkDelete` has been registered, so [`Self::bulk_delete`]
+    /// only pays for a `create_stored_procedure` round trip on first use.
+    bulk_delete_sproc_registered: AtomicBool,
 }
 
 /// Runtime configuration for the Azure Cosmos key-value store.
 #[derive(Deserialize)]
 pub struct AzureCosmosKeyValueRuntimeConfig {
-    /// The authorization token for the Azure Cosmos DB account.
-    pub key: String,
+    /// How to authenticate against the Azure Cosmos DB account.
+    #[serde(flatten)]
+    pub auth: AzureCosmosAuthConfig,
     /// The Azure Cosmos DB account name.
     pub account: String,
     /// The Azure Cosmos DB database.
     pub database: String,
     /// The Azure Cosmos DB container where data is stored.
-    /// The CosmosDB container must be created with the default partition key, /id
+    ///
+    /// The container must already be created with the partition key path
+    /// that matches this store's mode (`/store_id` when a `store_id` is in
+    /// play, `/id` otherwise) unless [`KeyValueAzureCosmos::new_with_provisioning`]
+    /// is used to provision it.
     pub container: String,
 }
 
+/// How to authenticate against the Azure Cosmos DB account.
+pub enum AzureCosmosAuthConfig {
+    /// Authenticate with an account primary/secondary key.
+    Key { key: String },
+    /// Authenticate with an Azure AD / managed-identity credential, e.g. one
+    /// built with `azure_identity::create_default_credential()`.
+    Aad { credential: Arc<dyn TokenCredential> },
+}
+
+impl<'de> Deserialize<'de> for AzureCosmosAuthConfig {
+    /// Only the `key` auth mode can be expressed as static runtime config;
+    /// the `Aad` variant is constructed in code by a host that already holds
+    /// a `TokenCredential`, not deserialized from a config file.
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct KeyOnly {
+            key: String,
+        }
+        Ok(AzureCosmosAuthConfig::Key {
+            key: KeyOnly::deserialize(deserializer)?.key,
+        })
+    }
+}
+
 impl KeyValueAzureCosmos {
-    pub fn new(
+    pub async fn new(
         app_id: Option<String>,
         store_id: Option<String>,
         config: AzureCosmosKeyValueRuntimeConfig,
     ) -> Result<Self> {
-        let token = AuthorizationToken::primary_key(config.key).map_err(log_error)?;
+        let token = auth_token(config.auth).await?;
         let cosmos_client = CosmosClient::new(config.account, token);
         let database_client = cosmos_client.database_client(config.database);
         let client = database_client.collection_client(config.container);
@@ -63,11 +117,76 @@ impl KeyValueAzureCosmos {
             client,
             app_id,
             store_id,
+            node_id: Uuid::new_v4().to_string(),
+            bulk_upsert_sproc_registered: AtomicBool::new(false),
+            bulk_delete_sproc_registered: AtomicBool::new(false),
         })
     }
+
+    /// Creates a new `KeyValueAzureCosmos`, creating the database and/or
+    /// container first if either is missing.
+    ///
+    /// The container is created with the partition key path that matches
+    /// this store's mode: `/store_id` when a `store_id` is in play,
+    /// otherwise `/id`. Creation is idempotent, so calling this on every
+    /// startup is safe even once the resources already exist.
+    ///
+    /// Requires the `control_plane` feature, since provisioning needs
+    /// permission to manage databases and containers rather than just
+    /// read/write documents, which a data-plane resource token won't grant.
+    ///
+    /// Note: containers created this way do NOT have the change feed
+    /// retention policy `KeyValueAzureCosmos::watch` needs -- that policy can
+    /// only be set at container-creation time, and isn't plumbed through
+    /// here. Provision the container with that policy out-of-band (e.g. via
+    /// the Azure CLI/portal) if you intend to call `watch` on it.
+    #[cfg(feature = "control_plane")]
+    pub async fn new_with_provisioning(
+        app_id: Option<String>,
+        store_id: Option<String>,
+        config: AzureCosmosKeyValueRuntimeConfig,
+    ) -> Result<Self> {
+        let token = auth_token(config.auth).await?;
+        let cosmos_client = CosmosClient::new(config.account.clone(), token);
+
+        match cosmos_client.create_database(&config.database).await {
+            Ok(_) => (),
+            Err(err) if is_conflict(&err) => (),
+            Err(err) => return Err(log_error(err)),
+        }
+
+        let database_client = cosmos_client.database_client(config.database);
+        // Must match `Pair`/`CausalPair`'s `CosmosEntity::partition_key`, which
+        // uses `store_id` (falling back to `id`) -- `app_id` plays no part in it.
+        let partition_key_path = if store_id.is_some() {
+            "/store_id"
+        } else {
+            "/id"
+        };
+        match database_client
+            .create_collection(&config.container, partition_key_path)
+            .await
+        {
+            Ok(_) => (),
+            Err(err) if is_conflict(&err) => (),
+            Err(err) => return Err(log_error(err)),
+        }
+
+        let client = database_client.collection_client(config.container);
+
+        Ok(Self {
+            client,
+            app_id,
+            store_id,
+            node_id: Uuid::new_v4().to_string(),
+            bulk_upsert_sproc_registered: AtomicBool::new(false),
+            bulk_delete_sproc_registered: AtomicBool::new(false),
+        })
+    }
+
     pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
         let pair = self.get_pair(key).await?;
-        Ok(pair.map(|p| p.value))
+        Ok(pair.map(|(p, _)| p.value))
     }
 
     pub async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
@@ -100,10 +219,9 @@ impl KeyValueAzureCosmos {
     }
 
     pub async fn get_many(&self, keys: Vec<String>) -> Result<Vec<(String, Option<Vec<u8>>)>> {
-        let stmt = Query::new(self.get_in_query(keys));
         let query = self
             .client
-            .query_documents(stmt)
+            .query_documents(self.get_in_query(keys))
             .query_cross_partition(true);
 
         let mut res = Vec::new();
@@ -119,24 +237,132 @@ impl KeyValueAzureCosmos {
         Ok(res)
     }
 
+    /// Upserts every pair in `key_values`.
+    ///
+    /// When the store uses a `store_id` partition key, every pair lands in
+    /// the same partition, so this runs as a single all-or-nothing
+    /// transactional batch via the `bulkUpsert` stored procedure. Otherwise
+    /// each key is its own partition and pairs are written one at a time.
     pub async fn set_many(&self, key_values: Vec<(String, Vec<u8>)>) -> Result<()> {
-        for (key, value) in key_values {
-            self.set(key.as_ref(), &value).await?
+        let pairs: Vec<Pair> = key_values
+            .into_iter()
+            .map(|(id, value)| Pair {
+                id,
+                value,
+                store_id: self.store_id.clone(),
+            })
+            .collect();
+
+        if self.store_id.is_some() {
+            return self.bulk_upsert(&pairs).await;
+        }
+
+        for pair in pairs {
+            self.client
+                .create_document(pair)
+                .is_upsert(true)
+                .await
+                .map_err(log_error)?;
         }
         Ok(())
     }
 
+    /// Deletes every key in `keys`.
+    ///
+    /// Same partitioning tradeoff as [`Self::set_many`]: a `store_id`
+    /// partition key lets every delete run as one transactional batch via
+    /// the `bulkDelete` stored procedure, otherwise deletes happen one at a
+    /// time.
     pub async fn delete_many(&self, keys: Vec<String>) -> Result<()> {
+        if self.store_id.is_some() {
+            return self.bulk_delete(&keys).await;
+        }
+
         for key in keys {
             self.delete(key.as_ref()).await?
         }
         Ok(())
     }
 
-    pub async fn get_pair(&self, key: &str) -> Result<Option<Pair>> {
+    /// Executes the `bulkUpsert` stored procedure against the partition
+    /// shared by every pair, registering it first if this store hasn't
+    /// already done so.
+    async fn bulk_upsert(&self, pairs: &[Pair]) -> Result<()> {
+        let partition_key = self
+            .store_id
+            .clone()
+            .expect("bulk_upsert requires a store_id partition key");
+        self.ensure_stored_procedure(
+            BULK_UPSERT_SPROC_NAME,
+            BULK_UPSERT_SPROC_BODY,
+            &self.bulk_upsert_sproc_registered,
+        )
+        .await?;
+        self.client
+            .stored_procedure_client(BULK_UPSERT_SPROC_NAME)
+            .execute_stored_procedure::<serde_json::Value, _>((pairs.to_vec(),))
+            .partition_key(partition_key)
+            .await
+            .map_err(log_error)?;
+        Ok(())
+    }
+
+    /// Executes the `bulkDelete` stored procedure against the partition
+    /// shared by every key, registering it first if this store hasn't
+    /// already done so.
+    async fn bulk_delete(&self, keys: &[String]) -> Result<()> {
+        let partition_key = self
+            .store_id
+            .clone()
+            .expect("bulk_delete requires a store_id partition key");
+        self.ensure_stored_procedure(
+            BULK_DELETE_SPROC_NAME,
+            BULK_DELETE_SPROC_BODY,
+            &self.bulk_delete_sproc_registered,
+        )
+        .await?;
+        self.client
+            .stored_procedure_client(BULK_DELETE_SPROC_NAME)
+            .execute_stored_procedure::<serde_json::Value, _>((keys.to_vec(),))
+            .partition_key(partition_key)
+            .await
+            .map_err(log_error)?;
+        Ok(())
+    }
+
+    /// Registers `body` under `name` if `registered` says this store
+    /// hasn't already done so, so repeat calls from the same store don't
+    /// each pay for a `create_stored_procedure` round trip before the
+    /// real one.
+    async fn ensure_stored_procedure(
+        &self,
+        name: &str,
+        body: &str,
+        registered: &AtomicBool,
+    ) -> Result<()> {
+        if registered.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        match self.client.create_stored_procedure(name, body).await {
+            Ok(_) => {
+                registered.store(true, Ordering::Release);
+                Ok(())
+            }
+            Err(err) if is_conflict(&err) => {
+                registered.store(true, Ordering::Release);
+                Ok(())
+            }
+            Err(err) => Err(log_error(err)),
+        }
+    }
+
+    /// Reads the pair stored for `key`, if any, together with its ETag so
+    /// callers can later write it back conditionally with
+    /// [`Self::set_if_match`] or [`Self::delete_if_match`].
+    pub async fn get_pair(&self, key: &str) -> Result<Option<(Pair, Etag)>> {
         let query = self
             .client
-            .query_documents(Query::new(self.get_query(key)))
+            .query_documents(self.get_query(key))
             .query_cross_partition(false)
             .max_item_count(1);
 
@@ -149,13 +375,163 @@ impl KeyValueAzureCosmos {
             .map_err(log_error)?
             .results
             .first()
-            .map(|(p, _)| p.clone()))
+            .map(|(pair, etag)| (pair.clone(), etag.clone())))
+    }
+
+    /// Writes `value` for `key` only if the document's current ETag still
+    /// matches `etag`, so two writers racing on the same key can coordinate
+    /// a read-modify-write without clobbering each other.
+    ///
+    /// Returns [`KeyValueAzureCosmosError::CasFailed`] (wrapped in the
+    /// returned error) if the document was changed by another writer since
+    /// `etag` was read.
+    pub async fn set_if_match(&self, key: &str, value: &[u8], etag: &Etag) -> Result<()> {
+        let pair = Pair {
+            id: key.to_string(),
+            value: value.to_vec(),
+            store_id: self.store_id.clone(),
+        };
+        let document_client = self
+            .client
+            .document_client(key, &self.store_id)
+            .map_err(log_error)?;
+        match document_client
+            .replace_document(pair)
+            .if_match_condition(IfMatchCondition::Match(etag.clone()))
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) if is_precondition_failed(&err) => {
+                Err(KeyValueAzureCosmosError::CasFailed.into())
+            }
+            Err(err) => Err(log_error(err)),
+        }
+    }
+
+    /// Deletes `key` only if the document's current ETag still matches
+    /// `etag`; see [`Self::set_if_match`] for the rationale.
+    pub async fn delete_if_match(&self, key: &str, etag: &Etag) -> Result<()> {
+        let document_client = self
+            .client
+            .document_client(key, &self.store_id)
+            .map_err(log_error)?;
+        match document_client
+            .delete_document()
+            .if_match_condition(IfMatchCondition::Match(etag.clone()))
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) if is_precondition_failed(&err) => {
+                Err(KeyValueAzureCosmosError::CasFailed.into())
+            }
+            Err(err) => Err(log_error(err)),
+        }
+    }
+
+    /// Reads the current sibling value(s) for `key` together with an opaque
+    /// causal context token describing what was read.
+    ///
+    /// More than one value means concurrent writers raced on this key and
+    /// the caller should reconcile them (e.g. by merging) before writing
+    /// back with [`Self::set_causal`], rather than one write silently
+    /// clobbering the other.
+    pub async fn get_causal(&self, key: &str) -> Result<Option<(Vec<Vec<u8>>, CausalContextToken)>> {
+        let Some((pair, _etag)) = self.get_causal_pair(key).await? else {
+            return Ok(None);
+        };
+        let token = encode_context(&pair.version_vector);
+        let values = pair.siblings.into_iter().map(|s| s.value).collect();
+        Ok(Some((values, token)))
+    }
+
+    /// Writes `value` for `key`, attributing it to this store's node and the
+    /// causal context previously returned by [`Self::get_causal`] (`None`
+    /// for a first write).
+    ///
+    /// Sibling values whose dot is causally dominated by `context` are
+    /// dropped; values written concurrently with `context` are kept
+    /// alongside the new one so a future `get_causal` surfaces every
+    /// unreconciled sibling instead of one write blindly overwriting
+    /// another. The merge is applied through an ETag `if-match` loop so two
+    /// nodes racing on the same key can't step on each other.
+    pub async fn set_causal(
+        &self,
+        key: &str,
+        value: &[u8],
+        context: Option<&CausalContextToken>,
+    ) -> Result<()> {
+        let incoming_vv = context.map(|c| decode_context(c)).transpose()?.unwrap_or_default();
+
+        loop {
+            let existing = self.get_causal_pair(key).await?;
+            let (version_vector, siblings, etag) = match existing {
+                Some((pair, etag)) => (pair.version_vector, pair.siblings, Some(etag)),
+                None => (BTreeMap::new(), Vec::new(), None),
+            };
+
+            let (version_vector, siblings) = merge_causal_write(
+                version_vector,
+                siblings,
+                &incoming_vv,
+                &self.node_id,
+                value.to_vec(),
+            );
+
+            let pair = CausalPair {
+                id: key.to_string(),
+                store_id: self.store_id.clone(),
+                siblings,
+                version_vector,
+            };
+
+            let result = match etag {
+                Some(etag) => {
+                    let document_client = self
+                        .client
+                        .document_client(key, &self.store_id)
+                        .map_err(log_error)?;
+                    document_client
+                        .replace_document(pair)
+                        .if_match_condition(IfMatchCondition::Match(etag))
+                        .await
+                        .map(|_| ())
+                }
+                // Not an upsert: if another writer created the document for
+                // this key first, we want the conflict below so we retry the
+                // merge against it, rather than silently clobbering it.
+                None => self.client.create_document(pair).await.map(|_| ()),
+            };
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(err) if is_precondition_failed(&err) || is_conflict(&err) => continue,
+                Err(err) => return Err(log_error(err)),
+            }
+        }
+    }
+
+    async fn get_causal_pair(&self, key: &str) -> Result<Option<(CausalPair, Etag)>> {
+        let query = self
+            .client
+            .query_documents(self.get_query(key))
+            .query_cross_partition(false)
+            .max_item_count(1);
+
+        let mut stream = query.into_stream::<CausalPair>();
+        let Some(res) = stream.next().await else {
+            return Ok(None);
+        };
+        Ok(res
+            .map_err(log_error)?
+            .results
+            .first()
+            .map(|(pair, etag)| (pair.clone(), etag.clone())))
     }
 
     pub async fn get_keys(&self) -> Result<Vec<String>> {
         let query = self
             .client
-            .query_documents(Query::new(self.get_keys_query()))
+            .query_documents(self.get_keys_query())
             .query_cross_partition(true);
         let mut res = Vec::new();
 
@@ -168,38 +544,113 @@ impl KeyValueAzureCosmos {
         Ok(res)
     }
 
-    fn get_query(&self, key: &str) -> String {
-        let mut query = format!("SELECT * FROM c WHERE c.id='{}'", key);
-        self.append_store_id(&mut query, true);
-        query
+    /// Subscribes to key changes via the Cosmos change feed in "all versions
+    /// and deletes" mode, scoped to this store's `store_id` partition when
+    /// one is set and, if `key_prefix` is given, further filtered to keys
+    /// starting with it.
+    ///
+    /// Each stream item is a page of changes together with a continuation
+    /// token; save the token and pass it back in as `continuation` (e.g.
+    /// after a restart) to resume from where a previous consumer left off
+    /// instead of replaying the whole feed.
+    ///
+    /// Note: "all versions and deletes" is a change feed retention policy
+    /// that must be set when the container is created -- a container
+    /// provisioned via `KeyValueAzureCosmos::new_with_provisioning` does not
+    /// have it set, and calling `watch` on one will fail at the first poll.
+    pub fn watch(
+        &self,
+        continuation: Option<String>,
+        key_prefix: Option<String>,
+    ) -> impl Stream<Item = Result<KeyChangeBatch>> + '_ {
+        let mut builder = self
+            .client
+            .get_change_feed()
+            .change_feed_mode(ChangeFeedMode::AllVersionsAndDeletes);
+        if let Some(store_id) = &self.store_id {
+            builder = builder.partition_key(store_id.clone());
+        }
+        if let Some(continuation) = continuation {
+            builder = builder.continuation(continuation);
+        }
+
+        builder.into_stream::<ChangeFeedItem<Pair>>().map(move |resp| {
+            let resp = resp.map_err(log_error)?;
+            let continuation = resp.continuation_token().to_string();
+            let changes = resp
+                .results
+                .into_iter()
+                .map(|(item, _)| item)
+                .filter(|item| {
+                    key_prefix
+                        .as_deref()
+                        .is_none_or(|prefix| item.key().starts_with(prefix))
+                })
+                .map(|item| match item.metadata.operation_type {
+                    ChangeFeedOperationType::Delete => Ok(KeyChange::Deleted {
+                        key: item.key().to_owned(),
+                    }),
+                    ChangeFeedOperationType::Create | ChangeFeedOperationType::Replace => {
+                        let key = item.key().to_owned();
+                        let pair = item.current.ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "change feed create/replace item for key '{key}' is missing `current`"
+                            )
+                        })?;
+                        Ok(KeyChange::Upserted {
+                            key: pair.id,
+                            value: pair.value,
+                        })
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(KeyChangeBatch {
+                changes,
+                continuation,
+            })
+        })
+    }
+
+    fn get_query(&self, key: &str) -> Query {
+        let mut query = "SELECT * FROM c WHERE c.id=@id".to_owned();
+        let mut params = vec![Param::new("@id".to_owned(), key.to_owned())];
+        self.append_store_id(&mut query, &mut params, true);
+        Query::new(query).with_params(params)
     }
 
-    fn get_keys_query(&self) -> String {
+    fn get_keys_query(&self) -> Query {
         let mut query = "SELECT * FROM c".to_owned();
-        self.append_store_id(&mut query, false);
-        query
+        let mut params = Vec::new();
+        self.append_store_id(&mut query, &mut params, false);
+        Query::new(query).with_params(params)
     }
 
-    fn get_in_query(&self, keys: Vec<String>) -> String {
-        let in_clause: String = keys
+    fn get_in_query(&self, keys: Vec<String>) -> Query {
+        let id_params: Vec<String> = (0..keys.len()).map(|i| format!("@id{i}")).collect();
+        let mut query = format!("SELECT * FROM c WHERE c.id IN ({})", id_params.join(", "));
+        let mut params: Vec<Param> = id_params
             .into_iter()
-            .map(|k| format!("'{k}'"))
-            .collect::<Vec<String>>()
-            .join(", ");
-
-        let mut query = format!("SELECT * FROM c WHERE c.id IN ({})", in_clause);
-        self.append_store_id(&mut query, true);
-        query
+            .zip(keys)
+            .map(|(name, key)| Param::new(name, key))
+            .collect();
+        self.append_store_id(&mut query, &mut params, true);
+        Query::new(query).with_params(params)
     }
 
-    fn append_store_id(&self, query: &mut String, condition_already_exists: bool) {
-        append_store_id_condition(query, self.store_id.as_deref(), condition_already_exists);
+    fn append_store_id(&self, query: &mut String, params: &mut Vec<Param>, condition_already_exists: bool) {
+        append_store_id_condition(
+            query,
+            params,
+            self.store_id.as_deref(),
+            condition_already_exists,
+        );
     }
 }
 
-/// Appends an option store id condition to the query.
+/// Appends an optional store id condition, bound as `@store_id`, to the query.
 fn append_store_id_condition(
     query: &mut String,
+    params: &mut Vec<Param>,
     store_id: Option<&str>,
     condition_already_exists: bool,
 ) {
@@ -209,9 +660,8 @@ fn append_store_id_condition(
         } else {
             query.push_str(" WHERE");
         }
-        query.push_str(" c.store_id='");
-        query.push_str(s);
-        query.push('\'')
+        query.push_str(" c.store_id=@store_id");
+        params.push(Param::new("@store_id".to_owned(), s.to_owned()));
     }
 }
 
@@ -230,7 +680,266 @@ impl CosmosEntity for Pair {
         self.store_id.clone().unwrap_or_else(|| self.id.clone())
     }
 }
+
+/// One change observed via [`KeyValueAzureCosmos::watch`].
+#[derive(Clone, Debug)]
+pub enum KeyChange {
+    /// `key` was written, taking on `value`.
+    Upserted { key: String, value: Vec<u8> },
+    /// `key` was removed.
+    Deleted { key: String },
+}
+
+/// One page of changes from [`KeyValueAzureCosmos::watch`], together with
+/// the continuation token to resume from after it.
+#[derive(Clone, Debug)]
+pub struct KeyChangeBatch {
+    pub changes: Vec<KeyChange>,
+    pub continuation: String,
+}
+
+/// One entry from a change feed opened in [`ChangeFeedMode::AllVersionsAndDeletes`]
+/// mode: the document as of this change (absent for deletes) plus metadata
+/// describing what happened.
+#[derive(Deserialize, Clone, Debug)]
+struct ChangeFeedItem<T> {
+    current: Option<T>,
+    previous: Option<T>,
+    metadata: ChangeFeedMetadata,
+}
+
+impl ChangeFeedItem<Pair> {
+    /// The id of the document this change applies to, whichever side of the
+    /// change (current or previous) still has it.
+    fn key(&self) -> &str {
+        self.current
+            .as_ref()
+            .or(self.previous.as_ref())
+            .map(|pair| pair.id.as_str())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct ChangeFeedMetadata {
+    operation_type: ChangeFeedOperationType,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+enum ChangeFeedOperationType {
+    Create,
+    Replace,
+    Delete,
+}
+
+/// An opaque causal context token returned by [`KeyValueAzureCosmos::get_causal`]
+/// and accepted back by [`KeyValueAzureCosmos::set_causal`]. Internally a
+/// base64-encoded version vector.
+pub type CausalContextToken = String;
+
+/// One sibling value in a [`CausalPair`], tagged with the dot (writer node
+/// id + that node's counter at the time of the write) that produced it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Sibling {
+    node_id: String,
+    counter: u64,
+    value: Vec<u8>,
+}
+
+/// The document shape used by the causal-consistency API: the concurrent
+/// sibling values for a key, plus the version vector summarising every dot
+/// this document has observed. Modeled on K2V's dotted version vector sets.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct CausalPair {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    store_id: Option<String>,
+    siblings: Vec<Sibling>,
+    #[serde(default)]
+    version_vector: BTreeMap<String, u64>,
+}
+
+impl CosmosEntity for CausalPair {
+    type Entity = String;
+
+    fn partition_key(&self) -> Self::Entity {
+        self.store_id.clone().unwrap_or_else(|| self.id.clone())
+    }
+}
+
+/// True if `version_vector` has already observed a counter at least as high
+/// as `counter` for `node_id`, i.e. the dot `(node_id, counter)` is stale
+/// and can be discarded as causally dominated.
+fn dot_dominated_by(version_vector: &BTreeMap<String, u64>, node_id: &str, counter: u64) -> bool {
+    version_vector.get(node_id).is_some_and(|&seen| seen >= counter)
+}
+
+/// Applies a causal write to `version_vector`/`siblings`: drops stored
+/// siblings whose dot `incoming_vv` already dominates, folds `incoming_vv`
+/// into the version vector, then appends `value` as a new sibling dotted
+/// with `writer_node_id`'s incremented counter. Siblings that are
+/// concurrent with (not dominated by) `incoming_vv` are left in place so
+/// the caller can see and reconcile them later.
+fn merge_causal_write(
+    mut version_vector: BTreeMap<String, u64>,
+    mut siblings: Vec<Sibling>,
+    incoming_vv: &BTreeMap<String, u64>,
+    writer_node_id: &str,
+    value: Vec<u8>,
+) -> (BTreeMap<String, u64>, Vec<Sibling>) {
+    siblings.retain(|s| !dot_dominated_by(incoming_vv, &s.node_id, s.counter));
+    for (node_id, counter) in incoming_vv {
+        let entry = version_vector.entry(node_id.clone()).or_insert(0);
+        *entry = (*entry).max(*counter);
+    }
+
+    let counter = version_vector.entry(writer_node_id.to_owned()).or_insert(0);
+    *counter += 1;
+    siblings.push(Sibling {
+        node_id: writer_node_id.to_owned(),
+        counter: *counter,
+        value,
+    });
+
+    (version_vector, siblings)
+}
+
+fn encode_context(version_vector: &BTreeMap<String, u64>) -> CausalContextToken {
+    let bytes = serde_json::to_vec(version_vector).expect("a version vector always serializes");
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
+
+fn decode_context(token: &CausalContextToken) -> Result<BTreeMap<String, u64>> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(token)
+        .map_err(log_error)?;
+    serde_json::from_slice(&bytes).map_err(log_error)
+}
+
+/// Builds the `AuthorizationToken` for whichever auth mode the config
+/// specifies, fetching an AAD token first if the `Aad` variant was given.
+async fn auth_token(auth: AzureCosmosAuthConfig) -> Result<AuthorizationToken> {
+    match auth {
+        AzureCosmosAuthConfig::Key { key } => AuthorizationToken::primary_key(key).map_err(log_error),
+        AzureCosmosAuthConfig::Aad { credential } => {
+            let token = credential
+                .get_token(&["https://cosmos.azure.com/.default"])
+                .await
+                .map_err(log_error)?;
+            // AAD tokens are bearer JWTs, not base64 HMAC signing secrets, so
+            // they go through the `aad` constructor rather than `primary_key`
+            // -- Cosmos sends these as a `type=aad` authorization header
+            // instead of an HMAC-signed one.
+            Ok(AuthorizationToken::aad(token.token.secret().to_string()))
+        }
+    }
+}
+
 pub fn log_error(err: impl std::fmt::Debug) -> anyhow::Error {
     println!("key-value error: {err:?}");
     anyhow::Error::msg("{err:?}")
 }
+
+/// True if `err` is an HTTP 409 Conflict, i.e. the resource being created
+/// already exists.
+fn is_conflict(err: &azure_core::Error) -> bool {
+    has_status(err, azure_core::StatusCode::Conflict)
+}
+
+/// True if `err` is an HTTP 412 Precondition Failed, i.e. an `if-match`
+/// condition didn't hold because the document changed underneath it.
+fn is_precondition_failed(err: &azure_core::Error) -> bool {
+    has_status(err, azure_core::StatusCode::PreconditionFailed)
+}
+
+fn has_status(err: &azure_core::Error, status: azure_core::StatusCode) -> bool {
+    matches!(err.as_http_error().map(|e| e.status()), Some(s) if s == status)
+}
+
+/// Errors specific to the Azure Cosmos key-value store, distinct from the
+/// generic errors that [`log_error`] produces for unexpected SDK failures.
+#[derive(Debug, thiserror::Error)]
+pub enum KeyValueAzureCosmosError {
+    /// A conditional write or delete lost a race: the document's ETag no
+    /// longer matched the one the caller read.
+    #[error("the document was modified by another writer since its ETag was read")]
+    CasFailed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dot_dominated_by_true_when_seen_counter_is_at_least_as_high() {
+        let vv = BTreeMap::from([("a".to_owned(), 3)]);
+        assert!(dot_dominated_by(&vv, "a", 1));
+        assert!(dot_dominated_by(&vv, "a", 3));
+        assert!(!dot_dominated_by(&vv, "a", 4));
+        assert!(!dot_dominated_by(&vv, "b", 1));
+    }
+
+    #[test]
+    fn context_round_trips_through_encode_decode() {
+        let vv = BTreeMap::from([("a".to_owned(), 2), ("b".to_owned(), 5)]);
+        let token = encode_context(&vv);
+        assert_eq!(decode_context(&token).unwrap(), vv);
+    }
+
+    #[test]
+    fn decode_context_rejects_non_base64_token() {
+        assert!(decode_context(&"not valid base64!!".to_owned()).is_err());
+    }
+
+    #[test]
+    fn merge_causal_write_on_first_write_creates_one_sibling() {
+        let (vv, siblings) =
+            merge_causal_write(BTreeMap::new(), Vec::new(), &BTreeMap::new(), "node-a", b"v1".to_vec());
+        assert_eq!(vv, BTreeMap::from([("node-a".to_owned(), 1)]));
+        assert_eq!(siblings.len(), 1);
+        assert_eq!(siblings[0].value, b"v1");
+    }
+
+    #[test]
+    fn merge_causal_write_with_seen_context_replaces_the_sibling_it_read() {
+        let vv = BTreeMap::from([("node-a".to_owned(), 1)]);
+        let siblings = vec![Sibling {
+            node_id: "node-a".to_owned(),
+            counter: 1,
+            value: b"v1".to_vec(),
+        }];
+
+        // The writer's context covers node-a@1, so that sibling is
+        // superseded rather than kept alongside the new write.
+        let (new_vv, new_siblings) =
+            merge_causal_write(vv, siblings, &BTreeMap::from([("node-a".to_owned(), 1)]), "node-a", b"v2".to_vec());
+
+        assert_eq!(new_vv, BTreeMap::from([("node-a".to_owned(), 2)]));
+        assert_eq!(new_siblings.len(), 1);
+        assert_eq!(new_siblings[0].value, b"v2");
+    }
+
+    #[test]
+    fn merge_causal_write_without_context_keeps_concurrent_sibling() {
+        let vv = BTreeMap::from([("node-a".to_owned(), 1)]);
+        let siblings = vec![Sibling {
+            node_id: "node-a".to_owned(),
+            counter: 1,
+            value: b"v1".to_vec(),
+        }];
+
+        // No context supplied means the writer never observed node-a@1, so
+        // its write is concurrent with it and both must survive as siblings.
+        let (new_vv, new_siblings) =
+            merge_causal_write(vv, siblings, &BTreeMap::new(), "node-b", b"v2".to_vec());
+
+        assert_eq!(
+            new_vv,
+            BTreeMap::from([("node-a".to_owned(), 1), ("node-b".to_owned(), 1)])
+        );
+        let mut values: Vec<&[u8]> = new_siblings.iter().map(|s| s.value.as_slice()).collect();
+        values.sort();
+        assert_eq!(values, vec![b"v1".as_slice(), b"v2".as_slice()]);
+    }
+}